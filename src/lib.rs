@@ -35,22 +35,130 @@
     issue_tracker_base_url = "https://github.com/tokio-rs/tracing/issues/"
 )]
 #[cfg(unix)]
-use std::{fmt, fmt::Write, io};
+use std::{
+    fmt,
+    fmt::Write,
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use libatrace::{trace_begin, trace_end, TRACE_BEGIN, TRACE_END};
+use libatrace::{
+    trace_begin, trace_end, TRACE_ASYNC_BEGIN, TRACE_ASYNC_END, TRACE_BEGIN, TRACE_COUNTER,
+    TRACE_END,
+};
 use tracing_core::{
     field::{Field, Visit},
     span::{Attributes, Id, Record},
-    Event, Subscriber,
+    Event, LevelFilter, Metadata, Subscriber,
 };
 
 use tracing::{field, Span};
 use tracing_futures::{Instrument, Instrumented};
 use tracing_subscriber::{layer::Context, registry::LookupSpan};
 
+/// The field name under which [`InstrumentExt::instrument`] records the
+/// per-future cookie used to derive the async cookie. Spans passed to
+/// `instrument()` must predeclare this field (e.g. with
+/// [`tracing::field::Empty`]) — see [`InstrumentExt`] for why.
+const FUTOBJ_FIELD: &str = "__fut";
+
+/// Derives a stable per-future cookie by hashing the `{:?}`-formatted
+/// value recorded by [`InstrumentExt::instrument`] — a process-wide
+/// monotonic counter value, unique per `instrument()` call, so every
+/// future gets a distinct cookie across its `poll`s no matter where its
+/// generated future happens to live on the stack (the original pointer
+/// to the by-value `instrument()` parameter was stable across loop
+/// iterations at the same call site, so every future spawned in a loop
+/// collided on one cookie; a counter can't collide that way).
+#[cfg(unix)]
+fn futobj_cookie(value: &dyn fmt::Debug) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    format!("{:?}", value).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Selects how span/event fields are rendered into the marker payload
+/// written by [`SpanVisitor`]/[`EventVisitor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The original, ad-hoc `key=value` rendering: fields comma-separated
+    /// in visiting order, values never quoted.
+    Default,
+    /// [logfmt](https://brandur.org/logfmt)-style rendering, as used by the
+    /// `tracing-logfmt` layer: `message` first, remaining fields as
+    /// space-separated `key=value` pairs, with values quoted only when
+    /// they contain whitespace or other special characters.
+    Logfmt,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Default
+    }
+}
+
+/// Quotes `value` logfmt-style, i.e. only when it contains characters that
+/// would otherwise make the payload ambiguous to parse back out.
+fn logfmt_quote(value: &str) -> String {
+    if value
+        .chars()
+        .any(|c| c.is_whitespace() || c == '"' || c == '=')
+    {
+        format!("{:?}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Appends a visitor's collected field data onto `buf` (which may already
+/// hold a span name or be empty, for events), per `format`. `message`, if
+/// present, is only ever set by [`Format::Logfmt`] visitors and always goes
+/// first; `data` holds the rest of the fields, already rendered by the
+/// visitor in the separator/quoting style appropriate to `format`.
+fn append_fields(buf: &mut String, format: Format, message: Option<String>, data: String) {
+    match format {
+        Format::Default => {
+            if !data.is_empty() {
+                if !buf.is_empty() {
+                    buf.push(',');
+                }
+                buf.push_str(&data);
+            }
+        }
+        Format::Logfmt => {
+            if let Some(msg) = message {
+                if !buf.is_empty() {
+                    buf.push(' ');
+                }
+                buf.push_str(&msg);
+            }
+            if !data.is_empty() {
+                if !buf.is_empty() {
+                    buf.push(' ');
+                }
+                buf.push_str(&data);
+            }
+        }
+    }
+}
+
 pub struct AtraceLayer {
     #[cfg(unix)]
     data_field: Option<String>,
+    #[cfg(unix)]
+    counter_target: Option<String>,
+    format: Format,
+    max_level: LevelFilter,
+    /// Maps `tracing` targets to an atrace tag bitmask (e.g. the
+    /// `ATRACE_TAG_*` categories), so targets can be scoped in and out via
+    /// [`with_enabled_tags`](AtraceLayer::with_enabled_tags).
+    #[cfg(unix)]
+    tags: Vec<(String, u64)>,
+    /// Bitmask of currently-active atrace tags. `0` (the default) disables
+    /// tag-based filtering entirely.
+    #[cfg(unix)]
+    enabled_tags: u64,
 }
 
 impl AtraceLayer {
@@ -59,7 +167,14 @@ impl AtraceLayer {
     pub fn new() -> io::Result<Self> {
         #[cfg(unix)]
         {
-            Ok(Self { data_field: None })
+            Ok(Self {
+                data_field: None,
+                counter_target: None,
+                format: Format::default(),
+                max_level: LevelFilter::TRACE,
+                tags: Vec::new(),
+                enabled_tags: 0,
+            })
         }
         #[cfg(not(unix))]
         Err(io::Error::new(
@@ -74,6 +189,128 @@ impl AtraceLayer {
         self.data_field = x;
         self
     }
+
+    /// Sets the event target treated as counter telemetry. Events recorded
+    /// against this target have their numeric fields emitted as ftrace
+    /// counter markers (`C|<pid>|<field>|<value>`), rendered as graphable
+    /// tracks in perfetto/systrace, instead of being written as a slice.
+    /// Defaults to `None`.
+    #[cfg(unix)]
+    pub fn with_counter_target(mut self, x: Option<String>) -> Self {
+        self.counter_target = x;
+        self
+    }
+
+    /// No-op on non-unix targets: `AtraceLayer::new()` always returns an
+    /// error there, so there's no layer to configure, but the builder
+    /// method must still exist (and typecheck) for cross-platform callers
+    /// that chain it unconditionally.
+    #[cfg(not(unix))]
+    pub fn with_counter_target(self, _x: Option<String>) -> Self {
+        self
+    }
+
+    /// Sets the payload formatting mode used for span and event field data.
+    /// Defaults to [`Format::Default`].
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the maximum level that reaches the (relatively expensive)
+    /// `tracing_mark_write` path; spans/events below it are skipped before
+    /// any formatting happens. Defaults to [`LevelFilter::TRACE`].
+    ///
+    /// # Note
+    ///
+    /// This is enforced via [`Layer::enabled`][tracing_subscriber::Layer::enabled]
+    /// and [`Layer::max_level_hint`][tracing_subscriber::Layer::max_level_hint],
+    /// which `tracing`/`tracing-subscriber` treat as a global decision for the
+    /// whole subscriber, not one scoped to this layer. If `AtraceLayer` is
+    /// composed with other layers (e.g. an stdout `fmt` layer), a level this
+    /// layer filters out is filtered out for *all* of them, not just atrace.
+    /// Don't set this below a level another layer in the stack needs.
+    pub fn with_max_level(mut self, level: LevelFilter) -> Self {
+        self.max_level = level;
+        self
+    }
+
+    /// Maps a `tracing` target to an atrace tag bitmask (e.g. the
+    /// `ATRACE_TAG_*` categories), so it can be scoped in or out via
+    /// [`with_enabled_tags`](Self::with_enabled_tags). Targets with no
+    /// mapping are unaffected by tag filtering.
+    #[cfg(unix)]
+    pub fn with_tag(mut self, target: impl Into<String>, tag: u64) -> Self {
+        self.tags.push((target.into(), tag));
+        self
+    }
+
+    /// No-op on non-unix targets: `AtraceLayer::new()` always returns an
+    /// error there, so there's no layer to configure, but the builder
+    /// method must still exist (and typecheck) for cross-platform callers
+    /// that chain it unconditionally.
+    #[cfg(not(unix))]
+    pub fn with_tag(self, _target: impl Into<String>, _tag: u64) -> Self {
+        self
+    }
+
+    /// Sets which atrace tag categories are currently active. A span/event
+    /// whose target is mapped (via [`with_tag`](Self::with_tag)) to a tag
+    /// that doesn't overlap this bitmask is skipped. Defaults to `0`, which
+    /// disables tag-based filtering: every target is emitted, subject only
+    /// to [`with_max_level`](Self::with_max_level).
+    ///
+    /// # Note
+    ///
+    /// Same caveat as [`with_max_level`](Self::with_max_level): this gates
+    /// [`Layer::enabled`][tracing_subscriber::Layer::enabled], which silences
+    /// a filtered-out span/event for every layer in the subscriber, not just
+    /// this one. Scope tags with that in mind when composing `AtraceLayer`
+    /// alongside other layers.
+    #[cfg(unix)]
+    pub fn with_enabled_tags(mut self, tags: u64) -> Self {
+        self.enabled_tags = tags;
+        self
+    }
+
+    /// No-op on non-unix targets; see [`with_tag`](Self::with_tag)'s
+    /// non-unix stub.
+    #[cfg(not(unix))]
+    pub fn with_enabled_tags(self, _tags: u64) -> Self {
+        self
+    }
+
+    /// Whether `metadata` passes this layer's level and tag filters.
+    fn is_enabled(&self, metadata: &Metadata<'_>) -> bool {
+        if !level_enabled(*metadata.level(), self.max_level) {
+            return false;
+        }
+        #[cfg(unix)]
+        if !tag_enabled(&self.tags, self.enabled_tags, metadata.target()) {
+            return false;
+        }
+        true
+    }
+}
+
+/// Whether `level` passes the `max_level` threshold.
+fn level_enabled(level: tracing_core::Level, max_level: LevelFilter) -> bool {
+    level <= max_level
+}
+
+/// Whether `target`'s mapped atrace tag (if any, per [`AtraceLayer::with_tag`])
+/// overlaps `enabled_tags`. `enabled_tags == 0` disables tag filtering
+/// entirely, and targets with no mapping in `tags` are always allowed
+/// through.
+#[cfg(unix)]
+fn tag_enabled(tags: &[(String, u64)], enabled_tags: u64, target: &str) -> bool {
+    if enabled_tags == 0 {
+        return true;
+    }
+    match tags.iter().find(|(t, _)| t == target).map(|(_, tag)| *tag) {
+        Some(tag) => tag & enabled_tags != 0,
+        None => true,
+    }
 }
 
 /// Construct a atrace layer
@@ -85,51 +322,116 @@ impl<S> tracing_subscriber::Layer<S> for AtraceLayer
 where
     S: Subscriber + for<'span> LookupSpan<'span>,
 {
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        self.is_enabled(metadata)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max_level)
+    }
+
     fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        if !self.is_enabled(attrs.metadata()) {
+            return;
+        }
         let span = ctx.span(id).expect("unknown span");
         let mut buf = String::new();
         write!(&mut buf, "{}", span.name()).unwrap();
 
         // for get all field value
         let mut data = String::new();
+        let mut message = None;
+        #[cfg(unix)]
+        let mut cookie = None;
         attrs.record(&mut SpanVisitor {
-            buf: &mut data,
-            futobj_field: None,
+            fields: FieldWriter {
+                buf: &mut data,
+                message: &mut message,
+                format: self.format,
+                first: true,
+            },
+            #[cfg(unix)]
+            futobj_field: Some(FUTOBJ_FIELD),
+            #[cfg(unix)]
+            cookie: &mut cookie,
         });
 
-        if !data.is_empty() {
-            write!(&mut buf, ",{}", data).unwrap();
-        }
-        span.extensions_mut().insert(SpanFields(buf));
+        append_fields(&mut buf, self.format, message, data);
+        span.extensions_mut().insert(SpanFields {
+            buf,
+            #[cfg(unix)]
+            cookie,
+            #[cfg(unix)]
+            async_begun: AtomicBool::new(false),
+        });
     }
 
     fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
         let span = ctx.span(id).expect("unknown span");
         let mut exts = span.extensions_mut();
-        let old_buf = &mut exts.get_mut::<SpanFields>().expect("missing fields").0;
+        // No `SpanFields`: this span was filtered out in `on_new_span`.
+        let fields = match exts.get_mut::<SpanFields>() {
+            Some(fields) => fields,
+            None => return,
+        };
+        let old_buf = &mut fields.buf;
 
         // try to get new update
         let mut buf = String::new();
         write!(&mut buf, "{}", span.name()).unwrap();
         let mut data = String::new();
+        let mut message = None;
+        #[cfg(unix)]
+        let mut cookie = None;
         values.record(&mut SpanVisitor {
-            buf: &mut data,
-            futobj_field: None,
+            fields: FieldWriter {
+                buf: &mut data,
+                message: &mut message,
+                format: self.format,
+                first: true,
+            },
+            #[cfg(unix)]
+            futobj_field: Some(FUTOBJ_FIELD),
+            #[cfg(unix)]
+            cookie: &mut cookie,
         });
-        if !data.is_empty() {
-            write!(&mut buf, ",{}", data).unwrap();
-        }
+        append_fields(&mut buf, self.format, message, data);
 
         // if have new update, update it
         if buf != old_buf.as_ref() {
             *old_buf = buf;
         }
+        #[cfg(unix)]
+        if let Some(cookie) = cookie {
+            fields.cookie = Some(cookie);
+        }
     }
 
     fn on_event(&self, event: &Event, _ctx: Context<S>) {
-        let mut buf = String::new();
+        if !self.is_enabled(event.metadata()) {
+            return;
+        }
+
+        #[cfg(unix)]
+        if self.counter_target.as_deref() == Some(event.metadata().target()) {
+            event.record(&mut CounterVisitor);
+            return;
+        }
+
+        let mut data = String::new();
+        let mut message = None;
         // Record event fields
-        event.record(&mut EventVisitor { buf: &mut buf });
+        event.record(&mut EventVisitor {
+            fields: FieldWriter {
+                buf: &mut data,
+                message: &mut message,
+                format: self.format,
+                first: true,
+            },
+        });
+
+        let mut buf = String::new();
+        append_fields(&mut buf, self.format, message, data);
 
         #[cfg(unix)]
         TRACE_BEGIN!("{}", &buf);
@@ -140,78 +442,292 @@ where
 
     fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
         let span = ctx.span(id).expect("expected: span id exists in registry");
+        if !self.is_enabled(span.metadata()) {
+            return;
+        }
         let exts = span.extensions();
-        let fields = exts.get::<SpanFields>().expect("missing fields");
+        // No `SpanFields`: this span was filtered out in `on_new_span`.
+        let fields = match exts.get::<SpanFields>() {
+            Some(fields) => fields,
+            None => return,
+        };
+
         #[cfg(unix)]
-        TRACE_BEGIN!("{}", &fields.0);
+        match fields.cookie {
+            // Async span: emit the begin marker once, on first entry. Later
+            // re-entries (across `.await` points) must not re-emit `S`, since
+            // the cookie already ties this slice's `S`/`F` pair together.
+            Some(cookie) if !fields.async_begun.swap(true, Ordering::Relaxed) => {
+                TRACE_ASYNC_BEGIN!("{}", span.name(), cookie);
+            }
+            Some(_) => {}
+            None => TRACE_BEGIN!("{}", &fields.buf),
+        }
     }
 
-    fn on_exit(&self, _id: &Id, _ctx: Context<'_, S>) {
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("expected: span id exists in registry");
+        let exts = span.extensions();
+        // No `SpanFields`: this span was filtered out in `on_new_span`.
+        let fields = match exts.get::<SpanFields>() {
+            Some(fields) => fields,
+            None => return,
+        };
+
+        // Async spans stay open (in ftrace terms) across `.await` points;
+        // they are only closed by `on_close`, via the `F` marker.
         #[cfg(unix)]
-        TRACE_END!();
+        if fields.cookie.is_none() {
+            TRACE_END!();
+        }
     }
 
     fn on_close(&self, id: Id, ctx: Context<S>) {
         let span = ctx.span(&id).expect("expected: span id exists in registry");
         let mut exts = span.extensions_mut();
-        exts.remove::<SpanFields>().expect("missing fields");
+        // No `SpanFields`: this span was filtered out in `on_new_span`.
+        let fields = match exts.remove::<SpanFields>() {
+            Some(fields) => fields,
+            None => return,
+        };
+
+        // Only emit `F` if `S` actually fired: a future that's created and
+        // recorded (so it has a cookie) but dropped before its first poll
+        // (cancelled, timed out, lost a `select!`) never enters the span,
+        // and an unmatched `F` would produce a dangling slice.
+        #[cfg(unix)]
+        if fields.async_begun.load(Ordering::Relaxed) {
+            if let Some(cookie) = fields.cookie {
+                TRACE_ASYNC_END!("{}", span.name(), cookie);
+            }
+        }
     }
 }
 
-struct SpanFields(String);
+struct SpanFields {
+    buf: String,
+    /// Cookie derived from the per-future id that `instrument()` records
+    /// into the `__fut` field (see [`futobj_cookie`]); `Some` marks
+    /// this span as async (driven through the `S`/`F` marker pair instead
+    /// of `B`/`E`). See [`InstrumentExt::instrument`].
+    #[cfg(unix)]
+    cookie: Option<u64>,
+    /// Guards against re-emitting the `S` marker on repeated `on_enter`
+    /// calls as an instrumented future is polled across `.await` points.
+    /// `AtomicBool` rather than `Cell<bool>` because `ExtensionsMut`
+    /// requires stored types to be `Send + Sync`.
+    #[cfg(unix)]
+    async_begun: AtomicBool,
+}
 
-struct SpanVisitor<'a> {
+/// Shared field-rendering state for [`SpanVisitor`]/[`EventVisitor`]:
+/// collects `record_*` callbacks into `buf` per `format`, pulling
+/// `message` out separately for [`Format::Logfmt`]. Factored out of the
+/// two visitors since they differ only in how they handle one field
+/// (spans additionally intercept [`FUTOBJ_FIELD`]); this is the entire
+/// format/quoting logic, so it lives in exactly one place.
+struct FieldWriter<'a> {
     buf: &'a mut String,
+    /// Holds the `message` field separately so [`Format::Logfmt`] can place
+    /// it first; unused (always `None`) in [`Format::Default`], where
+    /// `message` is written inline like any other field.
+    message: &'a mut Option<String>,
+    format: Format,
+    /// Tracks whether a field has already been written to `buf`, so
+    /// subsequent fields get a separator and the first one doesn't.
+    first: bool,
+}
+
+impl FieldWriter<'_> {
+    /// Writes one field according to `self.format`: comma-separated and
+    /// unquoted for [`Format::Default`], or space-separated and
+    /// logfmt-quoted (with `message` pulled out to go first) for
+    /// [`Format::Logfmt`]. Drops `tracing-log` shim fields (see
+    /// [`is_log_shim_field`]) regardless of which typed `Visit` method
+    /// routed them here.
+    fn write_field(&mut self, name: &str, value: &dyn fmt::Display) {
+        if is_log_shim_field(name) {
+            return;
+        }
+        match self.format {
+            Format::Default => {
+                if !self.first {
+                    self.buf.push_str(", ");
+                }
+                self.first = false;
+                if name == "message" {
+                    write!(self.buf, "{}", value).unwrap();
+                } else {
+                    write!(self.buf, "{}={}", name, value).unwrap();
+                }
+            }
+            Format::Logfmt => {
+                let formatted = logfmt_quote(&value.to_string());
+                if name == "message" {
+                    *self.message = Some(formatted);
+                } else {
+                    if !self.first {
+                        self.buf.push(' ');
+                    }
+                    self.first = false;
+                    write!(self.buf, "{}={}", name, formatted).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Whether `name` is `tracing-log` shim metadata that's already been
+/// accounted for elsewhere, and so should be dropped rather than written
+/// into the payload.
+fn is_log_shim_field(_name: &str) -> bool {
+    #[cfg(feature = "tracing-log")]
+    if _name.starts_with("log.") {
+        return true;
+    }
+    false
+}
+
+struct SpanVisitor<'a> {
+    fields: FieldWriter<'a>,
+    #[cfg(unix)]
     futobj_field: Option<&'a str>,
+    #[cfg(unix)]
+    cookie: &'a mut Option<u64>,
 }
 
 impl Visit for SpanVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.write_field(field.name(), &value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.write_field(field.name(), &value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.write_field(field.name(), &value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.write_field(field.name(), &value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.fields.write_field(field.name(), &value);
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.fields.write_field(field.name(), &value);
+    }
+
     fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        #[cfg(unix)]
         if let Some(futobj_field) = self.futobj_field {
             if futobj_field == field.name() {
-                write!(self.buf, "{:?}", value).unwrap();
-            }
-            return;
-        }
-        let buf = &mut self.buf;
-        let comma = "";
-        match field.name() {
-            "message" => {
-                write!(buf, "{} {:?}", comma, value).unwrap();
-            }
-            // Skip fields that are actually log metadata that have already been handled
-            #[cfg(feature = "tracing-log")]
-            name if name.starts_with("log.") => {}
-            name => {
-                write!(buf, "{} {}={:?}", comma, name, value).unwrap();
+                *self.cookie = Some(futobj_cookie(value));
+                return;
             }
         }
+        self.fields
+            .write_field(field.name(), &format_args!("{:?}", value));
     }
 }
 
 struct EventVisitor<'a> {
-    buf: &'a mut String,
+    fields: FieldWriter<'a>,
 }
 
 impl Visit for EventVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.write_field(field.name(), &value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.write_field(field.name(), &value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.write_field(field.name(), &value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.write_field(field.name(), &value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.fields.write_field(field.name(), &value);
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.fields.write_field(field.name(), &value);
+    }
+
     fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
-        let buf = &mut self.buf;
-        let comma = "";
-        match field.name() {
-            "message" => {
-                write!(buf, "{:?} {}", value, comma).unwrap();
-            }
-            // Skip fields that are actually log metadata that have already been handled
-            #[cfg(feature = "tracing-log")]
-            name if name.starts_with("log.") => {}
-            name => {
-                write!(buf, "{}={:?} {}", name, value, comma).unwrap();
-            }
-        }
+        self.fields
+            .write_field(field.name(), &format_args!("{:?}", value));
+    }
+}
+
+/// Visits only the numeric fields of a counter event, emitting each as its
+/// own ftrace counter marker. Unlike [`EventVisitor`], which assembles a
+/// single slice payload, every numeric field here becomes an independent
+/// `C|<pid>|<field>|<value>` line; non-numeric fields are dropped rather
+/// than stringified, since a counter track has no use for them.
+#[cfg(unix)]
+struct CounterVisitor;
+
+#[cfg(unix)]
+impl Visit for CounterVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        TRACE_COUNTER!("{}", field.name(), value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        TRACE_COUNTER!("{}", field.name(), value);
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        TRACE_COUNTER!("{}", field.name(), value);
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {
+        // Non-numeric fields don't map onto a counter track; ignore them.
     }
 }
 
+/// Extension trait for instrumenting a future so [`AtraceLayer`] traces it
+/// through its async-aware `S`/`F` marker pair (begin/end across the
+/// future's whole lifetime, spanning every `.await`) instead of the plain
+/// `B`/`E` pair an ordinary entered/exited span would otherwise get on
+/// every single `poll`.
+///
+/// # The span must predeclare the `__fut` field
+///
+/// `AtraceLayer` recognizes an instrumented span by a cookie recorded into
+/// a `__fut` field ([`FUTOBJ_FIELD`]). Because a `tracing` span's fieldset
+/// is fixed when the span is created, `span` must declare that field up
+/// front — with [`tracing::field::Empty`] as a placeholder — before it's
+/// passed to [`instrument`](Self::instrument):
+///
+/// ```ignore
+/// use tracing_libatrace::InstrumentExt;
+///
+/// let span = tracing::info_span!("my_task", __fut = tracing::field::Empty);
+/// tokio::spawn(my_future().instrument(span));
+/// ```
+///
+/// Calling `.instrument(span)` on a span built without a `__fut` field
+/// (e.g. a plain `tracing::info_span!("my_task")`) still compiles and
+/// runs, but `Span::record` silently drops the write to a field that
+/// isn't in the span's metadata: `AtraceLayer` never sees a cookie and
+/// falls back to the ordinary synchronous `B`/`E` pair, as if
+/// `.instrument()` had never been called.
 pub trait InstrumentExt: Instrument {
+    /// Instruments `self` with `span`, recording a cookie into `span`'s
+    /// `__fut` field so [`AtraceLayer`] can pair this future's begin/end
+    /// markers across `.await` points. See the trait docs for the span
+    /// shape this requires.
     fn instrument(self, span: Span) -> Instrumented<Self>;
 }
 
@@ -220,8 +736,141 @@ where
     T: Instrument + Sized,
 {
     fn instrument(self, span: Span) -> Instrumented<Self> {
-        let d = field::debug(&self as *const T);
-        span.record("__fut", &d);
+        // A process-wide counter, not the address of `self`: `self` is the
+        // by-value parameter of this very function, so at a given call site
+        // it lives at the same stack offset on every call (e.g. every
+        // iteration of `for _ in 0..n { spawn(make_fut().instrument(...)) }`),
+        // which would otherwise hand every future spawned there the same
+        // cookie.
+        static NEXT_FUTOBJ_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let id = NEXT_FUTOBJ_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let d = field::debug(id);
+        span.record(FUTOBJ_FIELD, &d);
         T::instrument(self, span)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logfmt_quote_leaves_plain_values_unquoted() {
+        assert_eq!(logfmt_quote("plain"), "plain");
+    }
+
+    #[test]
+    fn logfmt_quote_quotes_values_with_whitespace() {
+        assert_eq!(logfmt_quote("has space"), "\"has space\"");
+    }
+
+    #[test]
+    fn logfmt_quote_quotes_values_with_quotes_or_equals() {
+        assert_eq!(logfmt_quote("a=b"), "\"a=b\"");
+        assert_eq!(logfmt_quote(r#"a"b"#), r#""a\"b""#);
+    }
+
+    #[test]
+    fn append_fields_default_only_adds_comma_when_both_sides_nonempty() {
+        let mut buf = String::from("span_name");
+        append_fields(&mut buf, Format::Default, None, "a=1".to_string());
+        assert_eq!(buf, "span_name,a=1");
+
+        let mut no_name_buf = String::new();
+        append_fields(&mut no_name_buf, Format::Default, None, "a=1".to_string());
+        assert_eq!(no_name_buf, "a=1");
+
+        let mut no_data_buf = String::from("span_name");
+        append_fields(&mut no_data_buf, Format::Default, None, String::new());
+        assert_eq!(no_data_buf, "span_name");
+    }
+
+    #[test]
+    fn append_fields_logfmt_puts_message_before_data() {
+        let mut buf = String::new();
+        append_fields(
+            &mut buf,
+            Format::Logfmt,
+            Some("hello".to_string()),
+            "a=1".to_string(),
+        );
+        assert_eq!(buf, "hello a=1");
+    }
+
+    #[test]
+    fn append_fields_logfmt_without_message_just_writes_data() {
+        let mut buf = String::new();
+        append_fields(&mut buf, Format::Logfmt, None, "a=1".to_string());
+        assert_eq!(buf, "a=1");
+    }
+
+    #[test]
+    fn field_writer_default_separates_fields_with_comma_space() {
+        let mut buf = String::new();
+        let mut message = None;
+        let mut writer = FieldWriter {
+            buf: &mut buf,
+            message: &mut message,
+            format: Format::Default,
+            first: true,
+        };
+        writer.write_field("a", &1);
+        writer.write_field("b", &"two");
+        assert_eq!(buf, "a=1, b=two");
+        assert_eq!(message, None);
+    }
+
+    #[test]
+    fn field_writer_logfmt_extracts_message_and_quotes_data() {
+        let mut buf = String::new();
+        let mut message = None;
+        let mut writer = FieldWriter {
+            buf: &mut buf,
+            message: &mut message,
+            format: Format::Logfmt,
+            first: true,
+        };
+        writer.write_field("message", &"hello world");
+        writer.write_field("a", &1);
+        assert_eq!(message, Some("\"hello world\"".to_string()));
+        assert_eq!(buf, "a=1");
+    }
+
+    #[test]
+    fn level_enabled_filters_below_max_level() {
+        assert!(level_enabled(
+            tracing_core::Level::WARN,
+            LevelFilter::WARN
+        ));
+        assert!(level_enabled(
+            tracing_core::Level::ERROR,
+            LevelFilter::WARN
+        ));
+        assert!(!level_enabled(
+            tracing_core::Level::INFO,
+            LevelFilter::WARN
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tag_enabled_allows_unmapped_targets() {
+        let tags = vec![("mapped".to_string(), 0b01)];
+        assert!(tag_enabled(&tags, 0b10, "unmapped"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tag_enabled_disabled_filtering_allows_everything() {
+        let tags = vec![("mapped".to_string(), 0b01)];
+        assert!(tag_enabled(&tags, 0, "mapped"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tag_enabled_requires_overlap_for_mapped_targets() {
+        let tags = vec![("mapped".to_string(), 0b01)];
+        assert!(tag_enabled(&tags, 0b01, "mapped"));
+        assert!(!tag_enabled(&tags, 0b10, "mapped"));
+    }
+}